@@ -8,6 +8,16 @@
 //! Panic context lets you set value which is remembered, but not printed anywhere
 //! until panic occurs. It is also automatically forgotten at the end of scope.
 //!
+//! Set `PANIC_CONTEXT_BACKTRACE=1` (or `full`, for unabridged frames) to also
+//! capture a backtrace when each context entry is registered, in addition to
+//! its message and source location. Unlike the panic-site backtrace `std`
+//! prints, this shows the stack that *established* the still-live context,
+//! which can no longer be recovered once that frame has returned.
+//!
+//! By default the context block is printed above the usual panic message.
+//! Call `set_panic_context_formatter` to take full control of how the
+//! context entries and the panic itself are rendered together.
+//!
 //! # Example
 //!
 //! ```should_panic
@@ -43,8 +53,8 @@
 //!
 //! ```text
 //! Panic context:
-//! step: calculate signatures
-//! item: yo
+//! step: calculate signatures  (at src/main.rs:11:16)
+//! item: yo  (at src/main.rs:21:9)
 //! thread 'main' panicked at '...', src/libcore/str/mod.rs:2162
 //! note: Run with `RUST_BACKTRACE=1` for a backtrace.
 //! ```
@@ -55,8 +65,10 @@
 
 use std::panic;
 use std::collections::BTreeMap;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::backtrace::Backtrace;
 
 use std::io::Write;
 
@@ -64,15 +76,173 @@ lazy_static! {
     static ref INITIALIZED: Mutex<bool> = Mutex::new(false);
 }
 
+/// Controls whether a `std::backtrace::Backtrace` is captured alongside
+/// each context entry, mirroring `std`'s own `RUST_BACKTRACE` styles.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum BacktraceStyle {
+    Off = 0,
+    Short = 1,
+    Full = 2,
+}
+
+impl BacktraceStyle {
+    fn from_env() -> Self {
+        match std::env::var("PANIC_CONTEXT_BACKTRACE") {
+            Ok(ref value) if value == "full" => BacktraceStyle::Full,
+            Ok(ref value) if value != "0" => BacktraceStyle::Short,
+            _ => BacktraceStyle::Off,
+        }
+    }
+}
+
+// Cached once in `init()` so capturing a context entry doesn't pay the
+// cost of reading the environment (or capturing a backtrace at all when
+// the style is `Off`).
+static BACKTRACE_STYLE: AtomicU8 = AtomicU8::new(BacktraceStyle::Off as u8);
+
+fn backtrace_style() -> BacktraceStyle {
+    match BACKTRACE_STYLE.load(Ordering::Relaxed) {
+        1 => BacktraceStyle::Short,
+        2 => BacktraceStyle::Full,
+        _ => BacktraceStyle::Off,
+    }
+}
+
+struct Entry {
+    message: Option<String>,
+    location: &'static panic::Location<'static>,
+    backtrace: Option<Backtrace>,
+    // Whether `backtrace` was captured with `PANIC_CONTEXT_BACKTRACE=full`;
+    // `Backtrace`'s `Display` impl shows every frame when formatted with
+    // `{:#}` and a filtered, short trace with `{}`, so this decides which
+    // one `default_formatter` uses.
+    backtrace_full: bool,
+}
+
+/// A single registered context entry, as seen by a [`PanicContextFormatter`].
+///
+/// Entries are given to the formatter in the order they were registered.
+pub struct ContextEntry<'a> {
+    /// The message last set for this entry (via [`PanicContext::new`] or
+    /// [`UpdatablePanicContext::update`]).
+    pub message: &'a str,
+    /// Where this entry was registered.
+    pub location: &'static panic::Location<'static>,
+    /// The backtrace captured at registration time, if
+    /// `PANIC_CONTEXT_BACKTRACE` was set.
+    pub backtrace: Option<&'a Backtrace>,
+    /// Whether `backtrace` should be rendered with every frame
+    /// (`PANIC_CONTEXT_BACKTRACE=full`) rather than the short, filtered
+    /// form. Format `backtrace` with `{:#}` when this is `true`, `{}`
+    /// otherwise.
+    pub backtrace_full: bool,
+}
+
+/// Renders the active context entries, and optionally the panic itself,
+/// to `out`.
+///
+/// See [`set_panic_context_formatter`].
+pub type PanicContextFormatter = fn(&panic::PanicHookInfo, &[ContextEntry], &mut dyn Write);
+
+lazy_static! {
+    static ref FORMATTER: Mutex<PanicContextFormatter> = Mutex::new(default_formatter as PanicContextFormatter);
+}
+
+// Whether `set_panic_context_formatter` was ever called. Function pointers
+// aren't reliably comparable (addresses aren't unique across codegen units,
+// and distinct fns can be merged by the linker), so this is tracked
+// explicitly instead of comparing `*FORMATTER.lock().unwrap()` against
+// `default_formatter`.
+static CUSTOM_FORMATTER_INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// Overrides how the active context entries are rendered on panic.
+///
+/// The formatter is given the live [`PanicHookInfo`](panic::PanicHookInfo)
+/// along with the ordered context entries, and is responsible for writing
+/// whatever it wants to `out`; nothing is written on its behalf. This lets
+/// a downstream crate compose the panic message, its location and the
+/// context stack into a single coherent report instead of the two-part
+/// "context block, then the usual panic message" output this crate
+/// produces by default.
+///
+/// Installing a formatter other than the default one also suppresses the
+/// previously installed panic hook: since the formatter is given `info`
+/// and is expected to render the whole report itself, running the old
+/// hook afterwards would just print the standard message a second time.
+/// The default formatter does not have this effect, so until this is
+/// called, behavior is unchanged.
+pub fn set_panic_context_formatter(formatter: PanicContextFormatter) {
+    *FORMATTER.lock().unwrap() = formatter;
+    CUSTOM_FORMATTER_INSTALLED.store(true, Ordering::Relaxed);
+}
+
+fn default_formatter(_info: &panic::PanicHookInfo, entries: &[ContextEntry], out: &mut dyn Write) {
+    let _ = out.write(b"Panic context:\n");
+    for entry in entries {
+        let _ = out.write(format!("{}  (at {}:{}:{})\n",
+                                   entry.message,
+                                   entry.location.file(),
+                                   entry.location.line(),
+                                   entry.location.column()).as_bytes());
+        if let Some(backtrace) = entry.backtrace {
+            let rendered = if entry.backtrace_full {
+                format!("{:#}\n", backtrace)
+            } else {
+                format!("{}\n", backtrace)
+            };
+            let _ = out.write(rendered.as_bytes());
+        }
+    }
+}
+
 struct Values {
     next_id: usize,
-    values: BTreeMap<usize, String>,
+    values: BTreeMap<usize, Entry>,
 }
 thread_local! {
-    static VALUES: RefCell<Values> = RefCell::new(Values {
-        next_id: 0,
-        values: BTreeMap::new(),
-    });
+    static VALUES: RefCell<Values> = const {
+        RefCell::new(Values {
+            next_id: 0,
+            values: BTreeMap::new(),
+        })
+    };
+    static OUTPUT: RefCell<Option<Box<dyn Write + Send>>> = RefCell::new(None);
+    // Tracks nesting of our own panic hook, so a panic that occurs while the
+    // hook is still running (e.g. a custom formatter, or `previous_hook`
+    // itself, panicking) is detected and doesn't
+    // try to re-enter `VALUES`/`OUTPUT`.
+    static HOOK_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Redirects panic context output to `sink` for the current thread, instead
+/// of the real stderr.
+///
+/// This is mainly useful for testing: it lets a test capture the "Panic
+/// context:" block into a buffer without gagging the whole process's
+/// stderr, which would also swallow output from the test harness itself.
+///
+/// The previous sink, if any, is restored when the returned [`OutputGuard`]
+/// is dropped.
+pub fn capture_panic_context(sink: Box<dyn Write + Send>) -> OutputGuard {
+    let previous = OUTPUT.with(|output| output.borrow_mut().replace(sink));
+    OutputGuard { previous }
+}
+
+/// Restores the previous panic context output sink when dropped.
+///
+/// Returned by [`capture_panic_context`].
+#[must_use]
+pub struct OutputGuard {
+    previous: Option<Box<dyn Write + Send>>,
+}
+
+impl Drop for OutputGuard {
+    fn drop(&mut self) {
+        OUTPUT.with(|output| {
+            *output.borrow_mut() = self.previous.take();
+        });
+    }
 }
 
 /// Initializes the panic hook.
@@ -80,19 +250,63 @@ thread_local! {
 /// After this method is called, all panics will be logged rather than printed
 /// to standard error.
 fn init() {
+    BACKTRACE_STYLE.store(BacktraceStyle::from_env() as u8, Ordering::Relaxed);
+
     let previous_hook = panic::take_hook();
     panic::set_hook(Box::new(move |info| {
-        VALUES.with(|traces| {
-            let traces = traces.borrow();
-            let stderr = std::io::stderr();
-            let mut handle = stderr.lock();
-            let _ = handle.write(b"Panic context:\n");
-            for (_, value) in traces.values.iter() {
-                let _ = handle.write(value.as_bytes()).unwrap();
-                let _ = handle.write(b"\n").unwrap();
-            }
+        let nesting = HOOK_DEPTH.with(|depth| {
+            let nesting = depth.get();
+            depth.set(nesting + 1);
+            nesting
         });
-        previous_hook(info);
+
+        // On a nested panic (one that occurs while this very hook is still
+        // running), skip the context output entirely: `VALUES`/`OUTPUT` may
+        // still be borrowed by the frame that triggered the re-entrant
+        // panic, and trying to print context here would just trade a clean
+        // abort for a confusing `already borrowed` one.
+        //
+        // A custom formatter is expected to render the whole report itself
+        // (it is given `info`, same as `previous_hook` would be), so it
+        // replaces `previous_hook` rather than running before it; the
+        // default formatter leaves `previous_hook` in charge of the actual
+        // panic message, as before.
+        let mut handled = false;
+        if nesting == 0 {
+            VALUES.with(|traces| {
+                if let Ok(traces) = traces.try_borrow() {
+                    let entries: Vec<ContextEntry> = traces.values.values()
+                        .filter_map(|entry| entry.message.as_ref().map(|message| ContextEntry {
+                            message,
+                            location: entry.location,
+                            backtrace: entry.backtrace.as_ref(),
+                            backtrace_full: entry.backtrace_full,
+                        }))
+                        .collect();
+                    let formatter = *FORMATTER.lock().unwrap();
+                    OUTPUT.with(|output| {
+                        if let Ok(mut output) = output.try_borrow_mut() {
+                            if let Some(sink) = output.as_mut() {
+                                formatter(info, &entries, sink.as_mut());
+                            } else {
+                                let stderr = std::io::stderr();
+                                let mut handle = stderr.lock();
+                                formatter(info, &entries, &mut handle);
+                            }
+                        }
+                    });
+                    handled = CUSTOM_FORMATTER_INSTALLED.load(Ordering::Relaxed);
+                }
+            });
+        }
+
+        // Restore the depth only after `previous_hook` returns, so a panic
+        // out of `previous_hook` itself is still seen as nested (`nesting
+        // > 0`) rather than mistaken for a fresh, top-level panic.
+        if !handled {
+            previous_hook(info);
+        }
+        HOOK_DEPTH.with(|depth| depth.set(nesting));
     }));
 }
 
@@ -104,14 +318,17 @@ fn ensure_initialized() {
     }
 }
 
-fn add_entry(value: Option<String>) -> usize {
+fn add_entry(message: Option<String>, location: &'static panic::Location<'static>) -> usize {
+    let (backtrace, backtrace_full) = match backtrace_style() {
+        BacktraceStyle::Off => (None, false),
+        BacktraceStyle::Short => (Some(Backtrace::force_capture()), false),
+        BacktraceStyle::Full => (Some(Backtrace::force_capture()), true),
+    };
     VALUES.with(move |values| {
         let mut values = values.borrow_mut();
         let id = values.next_id;
         values.next_id += 1;
-        if let Some(v) = value {
-            values.values.insert(id, v);
-        }
+        values.values.insert(id, Entry { message, location, backtrace, backtrace_full });
         id
     })
 }
@@ -119,7 +336,9 @@ fn add_entry(value: Option<String>) -> usize {
 fn update_entry(id: usize, value: String) {
     VALUES.with(|values| {
         let mut values = values.borrow_mut();
-        values.values.insert(id, value);
+        if let Some(entry) = values.values.get_mut(&id) {
+            entry.message = Some(value);
+        }
     })
 }
 
@@ -129,9 +348,10 @@ pub struct UpdatablePanicContext {
     prefix: &'static str,
 }
 impl UpdatablePanicContext {
+    #[track_caller]
     pub fn new(prefix: &'static str) -> Self {
         ensure_initialized();
-        let id = add_entry(None);
+        let id = add_entry(None, panic::Location::caller());
         UpdatablePanicContext { id, prefix }
     }
 
@@ -147,16 +367,10 @@ pub struct PanicContext {
     id: usize,
 }
 impl PanicContext {
+    #[track_caller]
     pub fn new<T: Into<String>>(msg: T) -> Self {
         ensure_initialized();
-
-        let id = VALUES.with(|values| {
-            let mut values = values.borrow_mut();
-            let id = values.next_id;
-            values.next_id += 1;
-            values.values.insert(id, msg.into());
-            id
-        });
+        let id = add_entry(Some(msg.into()), panic::Location::caller());
         PanicContext { id }
     }
 }
@@ -199,11 +413,11 @@ impl Drop for PanicContext {
 ///
 /// ```text
 /// Panic context:
-/// step: calculate signatures
+/// step: calculate signatures  (at src/main.rs:1:12)
 /// thread 'main' panicked at '...', src/libcore/str/mod.rs:2162
 /// note: Run with `RUST_BACKTRACE=1` for a backtrace.
 /// ```
-
+#[track_caller]
 pub fn panic_context(prefix: &'static str) -> UpdatablePanicContext {
     UpdatablePanicContext::new(prefix)
 }
@@ -240,7 +454,7 @@ pub fn panic_context(prefix: &'static str) -> UpdatablePanicContext {
 ///
 /// ```text
 /// Panic context:
-/// item: cucumber
+/// item: cucumber  (at src/main.rs:3:5)
 /// thread 'main' panicked at '...', src/libcore/str/mod.rs:2162
 /// note: Run with `RUST_BACKTRACE=1` for a backtrace.
 /// ```
@@ -1,33 +1,43 @@
 #[macro_use] extern crate panic_context;
-#[macro_use] extern crate lazy_static;
-extern crate gag;
 
 use std::panic::{catch_unwind, UnwindSafe};
-use std::sync::Mutex;
-use gag::BufferRedirect;
+use std::sync::{Arc, Mutex};
+use std::io::Write;
 
-use std::io::Read;
+use panic_context::{panic_context, capture_panic_context};
 
-use panic_context::panic_context;
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
 
-lazy_static! {
-    // There may be only one active `gag` redirection but tests
-    // are executed in parallel by default, so we have to sync them.
-    static ref MUTEX: Mutex<()> = Mutex::new(());
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
 }
 
+// Each context line is suffixed with its `(at file:line:col)` source
+// location, which depends on where each test registers its context, so lines are compared by prefix
+// rather than equality.
 fn check_output<F: FnMut() -> () + UnwindSafe>(block: F, expected_output: &str) {
-    let _lock = MUTEX.lock().unwrap();
-    let mut buf = BufferRedirect::stderr().unwrap();
+    let buf = SharedBuffer::default();
+    let guard = capture_panic_context(Box::new(buf.clone()));
 
     let result = catch_unwind(block);
     assert!(result.is_err());
 
-    let mut output = String::new();
-    buf.read_to_string(&mut output).unwrap();
-    drop(buf);
+    drop(guard);
 
-    assert!(output.starts_with(expected_output));
+    let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let mut output_lines = output.lines();
+    for expected_line in expected_output.lines() {
+        let actual_line = output_lines.next().expect("missing output line");
+        assert!(actual_line.starts_with(expected_line),
+                "expected line starting with {:?}, got {:?}", expected_line, actual_line);
+    }
 }
 
 #[test]
@@ -59,3 +69,59 @@ fn update_value() {
                  },
                  "Panic context:\nstep: compilation\n");
 }
+
+#[test]
+fn records_source_location() {
+    let buf = SharedBuffer::default();
+    let guard = capture_panic_context(Box::new(buf.clone()));
+
+    // Keep this macro call and the `line!() + 2` below in sync: the context
+    // is registered where the macro is invoked, one line past `catch_unwind`.
+    let call_line = line!() + 2;
+    let result = catch_unwind(|| {
+                                  panic_context!("i={}", 1);
+                                  panic!("boom");
+                              });
+    assert!(result.is_err());
+
+    drop(guard);
+
+    let output = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+    let expected_prefix = format!("i=1  (at {}:{}:", file!(), call_line);
+    assert!(output.lines().any(|line| line.starts_with(&expected_prefix)),
+            "expected a line starting with {:?}, got:\n{}", expected_prefix, output);
+}
+
+// `set_panic_context_formatter` installs a process-wide formatter, so this
+// can't run in-process alongside the other tests without racing them. It
+// re-runs itself in a child process (selected via `--exact` plus an env
+// marker) that installs a sentinel previous hook, then a custom formatter,
+// and checks that only the formatter's output made it to stderr.
+#[test]
+fn custom_formatter_replaces_previous_hook() {
+    const CHILD_ENV: &str = "PANIC_CONTEXT_FORMATTER_CHILD";
+
+    if std::env::var(CHILD_ENV).is_ok() {
+        std::panic::set_hook(Box::new(|_| {
+            eprintln!("MARKER: previous hook ran");
+        }));
+        panic_context!("probe={}", 42);
+        panic_context::set_panic_context_formatter(|_info, entries, out| {
+            let _ = write!(out, "MARKER: formatter ran with {} entries\n", entries.len());
+        });
+        panic!("boom");
+    }
+
+    let exe = std::env::current_exe().unwrap();
+    let output = std::process::Command::new(exe)
+        .args(["custom_formatter_replaces_previous_hook", "--exact", "--nocapture"])
+        .env(CHILD_ENV, "1")
+        .output()
+        .expect("failed to run child process");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("MARKER: formatter ran with 1 entries"),
+            "custom formatter did not run: {}", stderr);
+    assert!(!stderr.contains("MARKER: previous hook ran"),
+            "previous hook ran even though a custom formatter was installed: {}", stderr);
+}